@@ -12,13 +12,171 @@
 //! Constant-time traits and utility functions.
 
 use core::ops::Neg;
+use core::ops::{BitAnd, BitOr, BitXor, Not};
+
+/// The result of a constant-time comparison, or a constant-time choice
+/// between two alternatives.
+///
+/// A `Choice` only ever holds `0u8` or `1u8`, but unlike a bare `u8` its
+/// value is routed through an optimization barrier when it is built from
+/// a `u8` (see `Choice::from`; `Choice::from_u8_unchecked` and the bitwise
+/// operators skip the barrier).  This stops an optimizing compiler from
+/// proving that the byte is boolean and "helpfully" turning the masked
+/// arithmetic below back into a data-dependent branch.
+///
+/// Code that builds masks out of choices (`CTAssignable`,
+/// `conditional_assign_u8`, etc.) should take a `Choice` rather than a
+/// raw `u8`.
+#[derive(Copy, Clone, Debug)]
+pub struct Choice(u8);
+
+impl Choice {
+    /// Build a `Choice` directly from a `0` or `1` literal, without
+    /// passing it through the optimization barrier.
+    ///
+    /// This is only for constructing `const` lookup tables (e.g. arrays
+    /// of `Choice` indexed by a public exponent bit) out of values that
+    /// are not themselves secret-dependent.  Anything derived from
+    /// runtime data should go through `Choice::from(u8)` instead, so
+    /// that the barrier is applied.
+    #[inline(always)]
+    pub const fn from_u8_unchecked(input: u8) -> Choice {
+        Choice(input)
+    }
+
+    /// Extract the inner byte, for code that still wants to build a
+    /// raw mask (e.g. `mask = choice.unwrap_u8().wrapping_neg()`).
+    #[inline(always)]
+    pub fn unwrap_u8(&self) -> u8 {
+        self.0
+    }
+}
+
+impl From<u8> for Choice {
+    /// Construct a `Choice` from a `u8`, passing it through an
+    /// optimization barrier first.
+    ///
+    /// # Inputs
+    ///
+    /// * `input`: must be `0u8` or `1u8`; any other value makes the
+    ///   resulting `Choice` meaningless.
+    #[inline(always)]
+    fn from(input: u8) -> Choice {
+        Choice(black_box(input))
+    }
+}
+
+impl BitAnd for Choice {
+    type Output = Choice;
+
+    #[inline(always)]
+    fn bitand(self, rhs: Choice) -> Choice {
+        Choice(self.0 & rhs.0)
+    }
+}
+
+impl BitOr for Choice {
+    type Output = Choice;
+
+    #[inline(always)]
+    fn bitor(self, rhs: Choice) -> Choice {
+        Choice(self.0 | rhs.0)
+    }
+}
+
+impl BitXor for Choice {
+    type Output = Choice;
+
+    #[inline(always)]
+    fn bitxor(self, rhs: Choice) -> Choice {
+        Choice(self.0 ^ rhs.0)
+    }
+}
+
+impl Not for Choice {
+    type Output = Choice;
+
+    #[inline(always)]
+    fn not(self) -> Choice {
+        Choice(1u8 ^ self.0)
+    }
+}
+
+/// An optimization barrier: feed `input` through it so the compiler
+/// cannot learn that the result only ever takes the values `0` or `1`.
+///
+/// On stable, this is a volatile read, which the compiler must assume
+/// can observe arbitrary memory and so cannot be reasoned about or
+/// elided.  With the `nightly` feature, an empty inline-asm block that
+/// merely takes `input` in a register is used instead, which is cheaper
+/// and harder for the optimizer to see through entirely.
+#[cfg(not(feature = "nightly"))]
+#[inline(never)]
+fn black_box(input: u8) -> u8 {
+    unsafe {
+        // Optimization barrier: the compiler cannot assume anything
+        // about a value it has just read back out of memory.
+        let ptr: *const u8 = &input;
+        ::core::ptr::read_volatile(ptr)
+    }
+}
+
+/// See the non-`nightly` definition above.
+#[cfg(feature = "nightly")]
+#[inline(never)]
+fn black_box(input: u8) -> u8 {
+    use core::arch::asm;
+    // The `reg` register class is arch-neutral (unlike the x86-only
+    // `reg_byte`), but doesn't accept 8-bit types, so widen to `u32`
+    // for the barrier and narrow back down afterwards.
+    let mut widened: u32 = input as u32;
+    unsafe {
+        asm!("/* {0} */", inout(reg) widened, options(nomem, nostack, preserves_flags));
+    }
+    widened as u8
+}
 
 /// Trait for items which can be conditionally assigned in constant time.
 pub trait CTAssignable {
-    /// If `choice == 1u8`, assign `other` to `self`.
+    /// If `choice == Choice::from(1u8)`, assign `other` to `self`.
     /// Otherwise, leave `self` unchanged.
     /// Executes in constant time.
-    fn conditional_assign(&mut self, other: &Self, choice: u8);
+    fn conditional_assign(&mut self, other: &Self, choice: Choice);
+}
+
+/// Trait for types which can be selected between, in constant time,
+/// without mutating either alternative.
+///
+/// This is the non-mutating counterpart to `CTAssignable`: instead of
+/// conditionally overwriting `self`, it returns a fresh value equal to
+/// one of the two inputs. Critically, `CTSelect` does not require
+/// `Self: Copy`, so heap-backed, variable-length types -- a
+/// `Vec`-backed bignum with a non-constant number of limbs, say -- can
+/// implement it too, by masking limb-by-limb instead of cloning one
+/// input up front. This is the piece `CTAssignable` alone is missing
+/// for clean constant-time table lookups, where the old pattern was to
+/// clone an element and then `conditional_assign` into it.
+pub trait CTSelect: Sized {
+    /// Select `a` or `b`, in constant time, returning a fresh value.
+    ///
+    /// # Inputs
+    ///
+    /// * If `choice == Choice::from(1u8)`, returns a value equal to `b`.
+    /// * Otherwise, if `choice == Choice::from(0u8)`, returns a value
+    ///   equal to `a`.
+    fn conditional_select(a: &Self, b: &Self, choice: Choice) -> Self;
+}
+
+impl<T> CTSelect for T where T: CTAssignable + Copy {
+    /// For `Copy` types -- the integer and fixed-array impls below --
+    /// keep the cheap in-register path: copy `a`, then conditionally
+    /// assign `b` over it.
+    #[inline(always)]
+    fn conditional_select(a: &T, b: &T, choice: Choice) -> T {
+        let mut selected: T = *a;
+        selected.conditional_assign(b, choice);
+        selected
+    }
 }
 
 /// Trait for items whose equality to another item may be tested in constant time.
@@ -37,20 +195,256 @@ pub trait CTEq {
 /// implementation is provided.
 pub trait CTNegatable
 {
-    /// Conditionally negate an element if `choice == 1u8`.
-    fn conditional_negate(&mut self, choice: u8);
+    /// Conditionally negate an element if `choice == Choice::from(1u8)`.
+    fn conditional_negate(&mut self, choice: Choice);
 }
 
 impl<T> CTNegatable for T
     where T: CTAssignable, for<'a> &'a T: Neg<Output=T>
 {
-    fn conditional_negate(&mut self, choice: u8) {
+    fn conditional_negate(&mut self, choice: Choice) {
         // Need to cast to eliminate mutability
         let self_neg: T = -(self as &T);
         self.conditional_assign(&self_neg, choice);
     }
 }
 
+/// A constant-time analogue of `Option<T>`.
+///
+/// Field inversion, point decompression, and square-root operations
+/// all need to return "maybe a value" without letting the success or
+/// failure of the operation leak through timing. A `CTOption` wraps a
+/// `T` alongside a `Choice` recording whether that `T` is meaningful;
+/// crucially, the wrapped `T` is always a fully-formed value -- even in
+/// the "none" case it is some well-defined placeholder -- so
+/// constructing, mapping, and selecting between `CTOption`s never
+/// branches on whether a value was actually present.
+#[derive(Copy, Clone, Debug)]
+pub struct CTOption<T> {
+    value: T,
+    is_some: Choice,
+}
+
+impl<T> CTOption<T> {
+    /// Wrap `value`, marking it present or absent according to `is_some`.
+    pub fn new(value: T, is_some: Choice) -> CTOption<T> {
+        CTOption { value, is_some }
+    }
+
+    /// Determine if this `CTOption` holds a value, in constant time.
+    #[inline(always)]
+    pub fn is_some(&self) -> Choice {
+        self.is_some
+    }
+
+    /// Determine if this `CTOption` is empty, in constant time.
+    #[inline(always)]
+    pub fn is_none(&self) -> Choice {
+        !self.is_some
+    }
+
+    /// Unwrap the contained value if present, otherwise `default`,
+    /// without branching on which case applies.
+    pub fn unwrap_or(mut self, default: T) -> T where T: CTAssignable {
+        self.value.conditional_assign(&default, self.is_none());
+        self.value
+    }
+
+    /// Map the contained value through `f`.
+    ///
+    /// `f` runs unconditionally; the result carries the same validity
+    /// as `self`, so mapping a "none" `CTOption` stays "none".
+    pub fn map<U, F>(self, f: F) -> CTOption<U> where F: FnOnce(T) -> U {
+        CTOption { value: f(self.value), is_some: self.is_some }
+    }
+
+    /// Chain another fallible, constant-time operation onto this one.
+    ///
+    /// `f` is always called, never skipped based on `self`'s validity;
+    /// the resulting `CTOption` is valid only if both `self` and the
+    /// `CTOption` returned by `f` were valid.
+    pub fn and_then<U, F>(self, f: F) -> CTOption<U> where F: FnOnce(T) -> CTOption<U> {
+        let inner = f(self.value);
+        CTOption { value: inner.value, is_some: self.is_some & inner.is_some }
+    }
+}
+
+impl<T: CTAssignable> CTAssignable for CTOption<T> {
+    fn conditional_assign(&mut self, other: &CTOption<T>, choice: Choice) {
+        self.value.conditional_assign(&other.value, choice);
+        let mut is_some = self.is_some.unwrap_u8();
+        conditional_assign_u8(&mut is_some, &other.is_some.unwrap_u8(), choice);
+        self.is_some = Choice::from(is_some);
+    }
+}
+
+impl<T: CTEq> CTEq for CTOption<T> {
+    fn ct_eq(&self, other: &CTOption<T>) -> u8 {
+        bytes_equal_ct(self.is_some.unwrap_u8(), other.is_some.unwrap_u8())
+            & self.value.ct_eq(&other.value)
+    }
+}
+
+/// A constant-time-safe three-valued comparison result.
+///
+/// Unlike `core::cmp::Ordering`, the inner byte is never matched on
+/// directly by callers; `is_lt`/`is_eq`/`is_gt` extract the answer as a
+/// `0u8`/`1u8` choice instead, so a `CTOrdering` can be produced and
+/// consumed without a data-dependent branch. Deliberately does not
+/// derive `PartialEq`/`Eq`: a variable-time `==` on the inner byte would
+/// let callers bypass that contract entirely.
+#[derive(Copy, Clone, Debug)]
+pub struct CTOrdering(u8);
+
+impl CTOrdering {
+    /// Returns `1u8` if this encodes "less than" (inner byte `0`), and
+    /// `0u8` otherwise.
+    #[inline(always)]
+    pub fn is_lt(&self) -> u8 {
+        bytes_equal_ct(self.0, 0)
+    }
+
+    /// Returns `1u8` if this encodes "equal" (inner byte `1`), and `0u8`
+    /// otherwise.
+    #[inline(always)]
+    pub fn is_eq(&self) -> u8 {
+        bytes_equal_ct(self.0, 1)
+    }
+
+    /// Returns `1u8` if this encodes "greater than" (inner byte `2`), and
+    /// `0u8` otherwise.
+    #[inline(always)]
+    pub fn is_gt(&self) -> u8 {
+        bytes_equal_ct(self.0, 2)
+    }
+}
+
+/// Trait for items whose relative order can be tested in constant time.
+pub trait CTOrd {
+    /// Compare `self` to `other`, in constant time.
+    ///
+    /// Implementations must scan the entire representation of `self`
+    /// and `other` regardless of where (or whether) they first differ,
+    /// so that the running time depends only on the length of the
+    /// encoding, never on its content.
+    fn ct_cmp(&self, other: &Self) -> CTOrdering;
+
+    /// Determine in constant time whether `self < other`.
+    ///
+    /// # Returns
+    ///
+    /// `1u8` if `self < other`, and `0u8` otherwise.
+    #[inline(always)]
+    fn ct_lt(&self, other: &Self) -> u8 {
+        self.ct_cmp(other).is_lt()
+    }
+
+    /// Determine in constant time whether `self > other`.
+    ///
+    /// # Returns
+    ///
+    /// `1u8` if `self > other`, and `0u8` otherwise.
+    #[inline(always)]
+    fn ct_gt(&self, other: &Self) -> u8 {
+        self.ct_cmp(other).is_gt()
+    }
+}
+
+/// Trait for types with a canonical, fixed-size little-endian byte
+/// encoding, used by the blanket `CTOrd` impl below so that scalars,
+/// field elements, and the like get constant-time ordering for free.
+pub trait CTOrdBytes {
+    /// Return this value's canonical 32-byte little-endian encoding.
+    fn to_ct_ord_bytes(&self) -> [u8; 32];
+}
+
+impl CTOrdBytes for [u8; 32] {
+    fn to_ct_ord_bytes(&self) -> [u8; 32] {
+        *self
+    }
+}
+
+impl<T: CTOrdBytes> CTOrd for T {
+    fn ct_cmp(&self, other: &T) -> CTOrdering {
+        ct_cmp_bytes(&self.to_ct_ord_bytes(), &other.to_ct_ord_bytes())
+    }
+}
+
+/// Compare two 32-byte little-endian encodings in constant time.
+///
+/// Folds a running "less/greater/equal so far" state across every byte
+/// of both arrays, always iterating the full length and only updating
+/// the result while `still_equal == 1`, so the timing does not depend
+/// on where (or whether) `a` and `b` first differ. The fold walks from
+/// the *most*-significant byte (index 31) down to the least-significant
+/// (index 0), since for a little-endian encoding it's the high-index
+/// end that determines the comparison first.
+fn ct_cmp_bytes(a: &[u8; 32], b: &[u8; 32]) -> CTOrdering {
+    let mut still_equal: u8 = 1;
+    let mut lt: u8 = 0;
+    let mut gt: u8 = 0;
+
+    for i in (0..32).rev() {
+        let x = a[i];
+        let y = b[i];
+        let differs = byte_is_nonzero(x ^ y);
+        // Borrow-propagation trick: 1 iff `x < y`.
+        let x_lt_y = byte_is_nonzero(
+            (x ^ ((x ^ y) | (x.wrapping_sub(y) ^ x))) >> 7);
+
+        lt |= still_equal & differs & x_lt_y;
+        gt |= still_equal & differs & (1u8 ^ x_lt_y);
+        still_equal &= 1u8 ^ differs;
+    }
+
+    CTOrdering(1u8.wrapping_sub(lt).wrapping_add(gt))
+}
+
+#[cfg(test)]
+mod ct_ord_tests {
+    use super::{CTOrd, CTOrdBytes};
+
+    #[test]
+    fn ct_cmp_equal() {
+        let a = [7u8; 32];
+        let b = [7u8; 32];
+        assert_eq!(a.ct_cmp(&b).is_eq(), 1u8);
+        assert_eq!(a.ct_lt(&b), 0u8);
+        assert_eq!(a.ct_gt(&b), 0u8);
+    }
+
+    #[test]
+    fn ct_cmp_differs_in_low_byte() {
+        // a = 2, b = 3: differ only in the least-significant byte.
+        let mut a = [0u8; 32];
+        let mut b = [0u8; 32];
+        a[0] = 2;
+        b[0] = 3;
+        assert_eq!(a.ct_lt(&b), 1u8);
+        assert_eq!(b.ct_gt(&a), 1u8);
+    }
+
+    #[test]
+    fn ct_cmp_differs_in_high_byte() {
+        // a = 2, b = 1281 = 0x0501: differ in both byte 0 and byte 1,
+        // but the high byte (index 1) must decide the comparison.
+        let mut a = [0u8; 32];
+        let mut b = [0u8; 32];
+        a[0] = 2;
+        b[0] = 1;
+        b[1] = 5;
+        assert_eq!(a.ct_lt(&b), 1u8);
+        assert_eq!(a.ct_gt(&b), 0u8);
+        assert_eq!(b.ct_gt(&a), 1u8);
+    }
+
+    #[test]
+    fn ct_cmp_to_ct_ord_bytes_roundtrip() {
+        let a = [9u8; 32];
+        assert_eq!(a.to_ct_ord_bytes(), a);
+    }
+}
+
 /// Check equality of two bytes in constant time.
 ///
 /// # Return
@@ -109,26 +503,26 @@ pub fn arrays_equal_ct(a: &[u8; 32], b: &[u8; 32]) -> u8 {
 ///
 /// # Inputs
 ///
-/// * If `choice == 1u8`, assign `other` to `this`.
-/// * Otherwise, if `choice == 0u8` leave `this` unchanged.
+/// * If `choice == Choice::from(1u8)`, assign `other` to `this`.
+/// * Otherwise, if `choice == Choice::from(0u8)` leave `this` unchanged.
 #[inline(always)]
-pub fn conditional_assign_u8(this: &mut u8, other: &u8, choice: &u8) {
-    let mask: u8 = -choice;
+pub fn conditional_assign_u8(this: &mut u8, other: &u8, choice: Choice) {
+    let mask: u8 = choice.unwrap_u8().wrapping_neg();
 
-    this ^= mask & (this ^ other);
+    *this ^= mask & (*this ^ other);
 }
 
 /// Conditionally assign an `other` `i8` to this `this` `i8`, in constant time.
 ///
 /// # Inputs
 ///
-/// * If `choice == 1u8`, assign `other` to `this`.
-/// * Otherwise, if `choice == 0u8` leave `this` unchanged.
+/// * If `choice == Choice::from(1u8)`, assign `other` to `this`.
+/// * Otherwise, if `choice == Choice::from(0u8)` leave `this` unchanged.
 #[inline(always)]
-pub fn conditional_assign_i8(this: &mut i8, other: &i8, choice: &u8) {
-    let mask: u8 = -choice;
+pub fn conditional_assign_i8(this: &mut i8, other: &i8, choice: Choice) {
+    let mask: i8 = (choice.unwrap_u8().wrapping_neg()) as i8;
 
-    this ^= (mask as i8) & (this ^ other);
+    *this ^= mask & (*this ^ other);
 }
 
 /// Compute the absolute value of `this` `i8` in constant time.
@@ -141,6 +535,114 @@ pub fn abs_i8(this: &i8) -> u8 {
     let negative: i8 = -this;
     let mut absolute: i8 = *this;
 
-    conditional_assign_i8(&mut absolute, &negative, &(-mask));
+    conditional_assign_i8(&mut absolute, &negative, Choice::from(mask));
     absolute as u8
 }
+
+/// Implement `CTAssignable` and `CTEq` for an integer type, generalizing
+/// the hand-written `conditional_assign_u8`/`conditional_assign_i8`
+/// above to every integer width.
+///
+/// `$u` is `$t`'s same-width unsigned type, used so that `ct_eq`'s
+/// bit-folding only ever does logical (never arithmetic/sign-extending)
+/// right shifts. `$bits` is `$t`'s width, so the fold can be unrolled
+/// down to a single bit regardless of which integer type is involved.
+macro_rules! impl_ct_integer {
+    ($(($t:ty, $u:ty, $bits:expr)),+ $(,)*) => {
+        $(
+            impl CTAssignable for $t {
+                #[inline(always)]
+                fn conditional_assign(&mut self, other: &$t, choice: Choice) {
+                    let mask: $t = (choice.unwrap_u8() as $t).wrapping_neg();
+                    *self ^= mask & (*self ^ *other);
+                }
+            }
+
+            impl CTEq for $t {
+                #[inline(always)]
+                fn ct_eq(&self, other: &$t) -> u8 {
+                    let mut x: $u = (*self ^ *other) as $u;
+                    let mut shift = $bits / 2;
+                    while shift > 0 {
+                        x |= x >> shift;
+                        shift /= 2;
+                    }
+                    1u8 ^ ((x & 1) as u8)
+                }
+            }
+        )+
+    };
+}
+
+impl_ct_integer!(
+    (u8, u8, 8), (u16, u16, 16), (u32, u32, 32), (u64, u64, 64),
+    (i8, u8, 8), (i16, u16, 16), (i32, u32, 32), (i64, u64, 64),
+);
+
+#[cfg(feature = "nightly")]
+impl_ct_integer!((u128, u128, 128), (i128, u128, 128));
+
+/// Implement `CTAssignable` and `CTEq` for `[$t; $n]`, for each array
+/// length `$n` given, by folding the per-element impls above
+/// element-wise across the whole array.
+macro_rules! impl_ct_array {
+    ($t:ty; $($n:expr),+ $(,)*) => {
+        $(
+            impl CTAssignable for [$t; $n] {
+                #[inline(always)]
+                fn conditional_assign(&mut self, other: &[$t; $n], choice: Choice) {
+                    for i in 0..$n {
+                        self[i].conditional_assign(&other[i], choice);
+                    }
+                }
+            }
+
+            impl CTEq for [$t; $n] {
+                #[inline(always)]
+                fn ct_eq(&self, other: &[$t; $n]) -> u8 {
+                    let mut x: u8 = 1;
+                    for i in 0..$n {
+                        x &= self[i].ct_eq(&other[i]);
+                    }
+                    x
+                }
+            }
+        )+
+    };
+}
+
+/// Instantiate `impl_ct_array!` for every integer type, for each array
+/// length `$n` given.
+macro_rules! impl_ct_array_for_all_integers {
+    ($($n:expr),+ $(,)*) => {
+        impl_ct_array!(u8; $($n),+);
+        impl_ct_array!(u16; $($n),+);
+        impl_ct_array!(u32; $($n),+);
+        impl_ct_array!(u64; $($n),+);
+        impl_ct_array!(i8; $($n),+);
+        impl_ct_array!(i16; $($n),+);
+        impl_ct_array!(i32; $($n),+);
+        impl_ct_array!(i64; $($n),+);
+    };
+}
+
+impl_ct_array_for_all_integers!(
+    1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+    17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32,
+);
+
+/// As `impl_ct_array_for_all_integers!`, but for the `nightly`-only
+/// `u128`/`i128` widths.
+#[cfg(feature = "nightly")]
+macro_rules! impl_ct_array_for_nightly_integers {
+    ($($n:expr),+ $(,)*) => {
+        impl_ct_array!(u128; $($n),+);
+        impl_ct_array!(i128; $($n),+);
+    };
+}
+
+#[cfg(feature = "nightly")]
+impl_ct_array_for_nightly_integers!(
+    1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+    17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32,
+);